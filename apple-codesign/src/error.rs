@@ -0,0 +1,43 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use thiserror::Error;
+
+/// Errors that can occur when interacting with Apple's code signing and
+/// notarization services.
+#[derive(Debug, Error)]
+pub enum AppleCodesignError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("HTTP error: {0}")]
+    Reqwest(#[from] reqwest::Error),
+
+    #[error("JSON error: {0}")]
+    SerdeJson(#[from] serde_json::Error),
+
+    #[error("JSON Web Token error: {0}")]
+    JsonWebToken(#[from] jsonwebtoken::errors::Error),
+
+    #[error("could not locate an App Store Connect API key")]
+    AppStoreConnectApiKeyNotFound,
+
+    #[error("notarization is not yet complete")]
+    NotarizeIncomplete,
+
+    #[error("notarization rejected (code {0}): {1}")]
+    NotarizeRejected(i64, String),
+
+    #[error("notarization was invalid")]
+    NotarizeInvalid,
+
+    #[error("failed to upload notarization submission: {0}")]
+    NotarizeUploadError(String),
+
+    #[error("timed out waiting for notarization to complete")]
+    NotarizeTimeout,
+
+    #[error("App Store Connect API error: {0}")]
+    AppStoreConnectApiError(String),
+}